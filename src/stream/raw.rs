@@ -6,7 +6,10 @@
 //! They are mostly thin wrappers around `zstd_safe::{DStream, CStream}`.
 use std::io;
 
-use zstd_safe::{self, CStream, DStream, InBuffer, OutBuffer};
+use zstd_safe::{
+    self, CDict, CParameter, CStream, DDict, DParameter, DStream, InBuffer,
+    OutBuffer,
+};
 
 use parse_code;
 
@@ -113,6 +116,43 @@ pub struct Status {
     pub bytes_written: usize,
 }
 
+/// A dictionary, pre-digested for compression at a given level.
+///
+/// Re-digesting raw dictionary bytes on every `Encoder` creation is wasteful
+/// when compressing many small inputs with the same dictionary. Building a
+/// `PreparedDict` once and sharing it (for instance through an `Arc`) across
+/// many `Encoder`s amortizes that cost instead.
+pub struct PreparedDict {
+    cdict: CDict<'static>,
+}
+
+impl PreparedDict {
+    /// Digests the given dictionary, to be used for compression at the
+    /// given level.
+    pub fn new(dictionary: &[u8], level: i32) -> Self {
+        PreparedDict {
+            cdict: zstd_safe::create_cdict(dictionary, level),
+        }
+    }
+}
+
+/// A dictionary, pre-digested for decompression.
+///
+/// See `PreparedDict` for why this is useful: it lets a `Decoder` reference
+/// an already-digested dictionary instead of re-digesting the raw bytes.
+pub struct PreparedDDict {
+    ddict: DDict<'static>,
+}
+
+impl PreparedDDict {
+    /// Digests the given dictionary.
+    pub fn new(dictionary: &[u8]) -> Self {
+        PreparedDDict {
+            ddict: zstd_safe::create_ddict(dictionary),
+        }
+    }
+}
+
 /// An in-memory decoder for streams of data.
 pub struct Decoder {
     context: DStream,
@@ -138,6 +178,48 @@ impl Decoder {
         ))?;
         Ok(Decoder { context })
     }
+
+    /// Creates a new decoder, using an already-digested dictionary.
+    ///
+    /// This avoids re-digesting the dictionary's raw bytes, and is worth it
+    /// when the same dictionary is shared across many `Decoder`s.
+    pub fn with_prepared_dictionary(
+        dictionary: &PreparedDDict,
+    ) -> io::Result<Self> {
+        let mut context = zstd_safe::create_dstream();
+        parse_code(zstd_safe::init_dstream_using_ddict(
+            &mut context,
+            &dictionary.ddict,
+        ))?;
+        Ok(Decoder { context })
+    }
+
+    /// Sets an advanced decoding parameter on this decoder.
+    ///
+    /// This must be called before the first call to `run`: once
+    /// decompression has started, the underlying `DStream` parameters are
+    /// locked in.
+    pub fn set_parameter(&mut self, parameter: DParameter) -> io::Result<()> {
+        parse_code(zstd_safe::dctx_set_parameter(&mut self.context, parameter))?;
+        Ok(())
+    }
+
+    /// Returns the content size declared in a frame's header, if any.
+    ///
+    /// `src` only needs to contain the frame's first few bytes (enough to
+    /// cover its header), letting callers pre-size their output buffer
+    /// before decoding. Returns `None` if the frame doesn't declare a
+    /// content size (for instance because it was produced without
+    /// `Encoder::set_pledged_src_size`), or if `src` isn't a valid frame
+    /// header.
+    pub fn content_size(src: &[u8]) -> Option<u64> {
+        match zstd_safe::get_frame_content_size(src) {
+            zstd_safe::CONTENTSIZE_UNKNOWN | zstd_safe::CONTENTSIZE_ERROR => {
+                None
+            }
+            size => Some(size),
+        }
+    }
 }
 
 impl Operation for Decoder {
@@ -175,6 +257,87 @@ impl Encoder {
         ))?;
         Ok(Encoder { context })
     }
+
+    /// Creates a new encoder, using an already-digested dictionary.
+    ///
+    /// This avoids re-digesting the dictionary's raw bytes, and is worth it
+    /// when the same dictionary is shared across many `Encoder`s.
+    pub fn with_prepared_dictionary(
+        dictionary: &PreparedDict,
+    ) -> io::Result<Self> {
+        let mut context = zstd_safe::create_cstream();
+        parse_code(zstd_safe::init_cstream_using_cdict(
+            &mut context,
+            &dictionary.cdict,
+        ))?;
+        Ok(Encoder { context })
+    }
+
+    /// Sets an advanced compression parameter on this encoder.
+    ///
+    /// This covers things like window/hash/chain/search log, the
+    /// content-size and checksum flags, and the dictionary-ID flag, none of
+    /// which are reachable through `new`/`with_dictionary` alone. It must be
+    /// called before the first call to `run`, as `CStream` parameters are
+    /// locked in once compression starts.
+    pub fn set_parameter(&mut self, parameter: CParameter) -> io::Result<()> {
+        parse_code(zstd_safe::cctx_set_parameter(&mut self.context, parameter))?;
+        Ok(())
+    }
+
+    /// Sets the number of worker threads used for compression.
+    ///
+    /// With `workers > 0`, compression happens in background threads and
+    /// `run` may return before all of its input has actually been
+    /// compressed, reporting a nonzero "remaining" hint while those threads
+    /// are still catching up. Output can also lag behind input, so callers
+    /// must keep pumping `flush`/`finish` until they return `Ok(0)` rather
+    /// than assuming one call drains everything, exactly as with a single
+    /// worker.
+    ///
+    /// This requires `zstd` to have been built with multithreading support;
+    /// otherwise this call will return an error.
+    pub fn set_workers(&mut self, workers: u32) -> io::Result<()> {
+        self.set_parameter(CParameter::NbWorkers(workers))
+    }
+
+    /// Tells the encoder in advance how many bytes it should expect through
+    /// `run`.
+    ///
+    /// This gets recorded in the produced frame's header, where it lets
+    /// decoders such as `Decoder::content_size` learn the content size
+    /// upfront and size their output buffer accordingly. Pass `None` to
+    /// mark the size as unknown again. Must be called before the first call
+    /// to `run`.
+    ///
+    /// If the total number of bytes actually fed through `run` doesn't
+    /// match the pledged size, `finish` will return an error, matching
+    /// zstd's own contract.
+    pub fn set_pledged_src_size(
+        &mut self,
+        pledged_src_size: Option<u64>,
+    ) -> io::Result<()> {
+        parse_code(zstd_safe::cctx_set_pledged_src_size(
+            &mut self.context,
+            pledged_src_size.unwrap_or(zstd_safe::CONTENTSIZE_UNKNOWN),
+        ))?;
+        Ok(())
+    }
+
+    /// Resets this encoder's parameters (window log, worker count, etc.)
+    /// back to their defaults, in addition to starting a new, independent
+    /// frame.
+    ///
+    /// Use this instead of `Operation::reinit` when the encoder also needs
+    /// to be reconfigured between frames, rather than just restarted with
+    /// its current parameters.
+    pub fn reinit_with_parameters(&mut self) -> io::Result<()> {
+        parse_code(zstd_safe::cctx_reset(
+            &mut self.context,
+            zstd_safe::ResetDirective::ZSTD_reset_session_and_parameters,
+        ))?;
+        Ok(())
+    }
 }
 
 impl Operation for Encoder {
@@ -197,12 +360,20 @@ impl Operation for Encoder {
     fn finish(&mut self, output: &mut OutBuffer) -> io::Result<usize> {
         parse_code(zstd_safe::end_stream(&mut self.context, output))
     }
+
+    fn reinit(&mut self) -> io::Result<()> {
+        parse_code(zstd_safe::cctx_reset(
+            &mut self.context,
+            zstd_safe::ResetDirective::ZSTD_reset_session_only,
+        ))?;
+        Ok(())
+    }
 }
 
 #[cfg(test)]
 mod tests {
-    use super::{Decoder, Encoder, Operation};
-    use zstd_safe::{InBuffer, OutBuffer};
+    use super::{Decoder, Encoder, Operation, PreparedDDict, PreparedDict};
+    use zstd_safe::{CParameter, DParameter, InBuffer, OutBuffer};
 
     #[test]
     fn test_cycle() {
@@ -242,4 +413,274 @@ mod tests {
 
         assert_eq!(initial_data, output.as_slice());
     }
+
+    #[test]
+    fn test_parameters() {
+        let mut encoder = Encoder::new(1).unwrap();
+        encoder.set_parameter(CParameter::ChecksumFlag(true)).unwrap();
+        encoder.set_parameter(CParameter::WindowLog(20)).unwrap();
+
+        let mut decoder = Decoder::new().unwrap();
+        decoder.set_parameter(DParameter::WindowLogMax(20)).unwrap();
+
+        let mut input = InBuffer::around(b"AbcdefAbcdefabcdef");
+
+        let mut output = [0u8; 128];
+        let mut output = OutBuffer::around(&mut output);
+
+        loop {
+            encoder.run(&mut input, &mut output).unwrap();
+
+            if input.pos == input.src.len() {
+                break;
+            }
+        }
+        encoder.finish(&mut output).unwrap();
+
+        let initial_data = input.src;
+
+        let mut input = InBuffer::around(output.as_slice());
+        let mut output = [0u8; 128];
+        let mut output = OutBuffer::around(&mut output);
+
+        loop {
+            decoder.run(&mut input, &mut output).unwrap();
+
+            if input.pos == input.src.len() {
+                break;
+            }
+        }
+
+        assert_eq!(initial_data, output.as_slice());
+    }
+
+    #[test]
+    fn test_workers() {
+        let mut encoder = Encoder::new(1).unwrap();
+
+        // Multithreaded compression is an opt-in zstd build option, not the
+        // default: on a build without it, `set_workers` errors out rather
+        // than silently falling back to single-threaded mode. Skip the test
+        // there instead of unwrapping blind and failing on every ordinary
+        // build.
+        if encoder.set_workers(2).is_err() {
+            return;
+        }
+
+        // Keep individual jobs small so a large input actually gets split
+        // across background worker threads, instead of completing as a
+        // single in-line job indistinguishable from non-MT compression.
+        encoder.set_parameter(CParameter::JobSize(1 << 10)).unwrap();
+
+        // Large enough to span several jobs, so there's work to spread across
+        // workers. The data is a short repeating pattern, so it compresses
+        // very well.
+        let data: Vec<u8> = (0..4_000_000u32).map(|i| (i % 251) as u8).collect();
+
+        let mut compressed = Vec::new();
+        let mut input = InBuffer::around(data.as_slice());
+        let mut saw_nonzero_remaining = false;
+
+        loop {
+            let mut buf = [0u8; 4096];
+            let mut output = OutBuffer::around(&mut buf);
+            let remaining = encoder.run(&mut input, &mut output).unwrap();
+            saw_nonzero_remaining |= remaining != 0;
+            compressed.extend_from_slice(output.as_slice());
+
+            if input.pos == input.src.len() {
+                break;
+            }
+        }
+
+        // With workers enabled, output can lag behind input: keep pumping
+        // `flush`/`finish` until they report there's nothing left, rather
+        // than assuming a single call drains everything.
+        let mut flush_iterations = 0;
+        loop {
+            let mut buf = [0u8; 4096];
+            let mut output = OutBuffer::around(&mut buf);
+            let remaining = encoder.flush(&mut output).unwrap();
+            compressed.extend_from_slice(output.as_slice());
+            flush_iterations += 1;
+
+            if remaining == 0 {
+                break;
+            }
+        }
+
+        let mut finish_iterations = 0;
+        loop {
+            let mut buf = [0u8; 4096];
+            let mut output = OutBuffer::around(&mut buf);
+            let remaining = encoder.finish(&mut output).unwrap();
+            compressed.extend_from_slice(output.as_slice());
+            finish_iterations += 1;
+
+            if remaining == 0 {
+                break;
+            }
+        }
+
+        // Check that the exchange took more than a single step: either `run`
+        // returned a nonzero hint, or `flush`/`finish` needed more than one
+        // call. `run`'s return value is a hint for the next input size (see
+        // `Status::remaining`), so a nonzero value alone doesn't show that
+        // output lagged behind input.
+        assert!(
+            saw_nonzero_remaining
+                || flush_iterations > 1
+                || finish_iterations > 1
+        );
+
+        let mut decoder = Decoder::new().unwrap();
+        let mut input = InBuffer::around(compressed.as_slice());
+        let mut decompressed = Vec::new();
+
+        loop {
+            let mut buf = [0u8; 4096];
+            let mut output = OutBuffer::around(&mut buf);
+            decoder.run(&mut input, &mut output).unwrap();
+            decompressed.extend_from_slice(output.as_slice());
+
+            if input.pos == input.src.len() {
+                break;
+            }
+        }
+
+        assert_eq!(data, decompressed);
+    }
+
+    #[test]
+    fn test_prepared_dictionary() {
+        let dictionary = b"AbcdefAbcdefabcdef";
+        let cdict = PreparedDict::new(dictionary, 1);
+        let ddict = PreparedDDict::new(dictionary);
+
+        let mut encoder = Encoder::with_prepared_dictionary(&cdict).unwrap();
+        let mut decoder = Decoder::with_prepared_dictionary(&ddict).unwrap();
+
+        let mut input = InBuffer::around(b"AbcdefAbcdefabcdef");
+
+        let mut output = [0u8; 128];
+        let mut output = OutBuffer::around(&mut output);
+
+        loop {
+            encoder.run(&mut input, &mut output).unwrap();
+
+            if input.pos == input.src.len() {
+                break;
+            }
+        }
+        encoder.finish(&mut output).unwrap();
+
+        let initial_data = input.src;
+
+        let mut input = InBuffer::around(output.as_slice());
+        let mut output = [0u8; 128];
+        let mut output = OutBuffer::around(&mut output);
+
+        loop {
+            decoder.run(&mut input, &mut output).unwrap();
+
+            if input.pos == input.src.len() {
+                break;
+            }
+        }
+
+        assert_eq!(initial_data, output.as_slice());
+    }
+
+    #[test]
+    fn test_reinit() {
+        let mut encoder = Encoder::new(1).unwrap();
+
+        let compress = |encoder: &mut Encoder, data: &[u8]| {
+            let mut input = InBuffer::around(data);
+            let mut output = [0u8; 128];
+            let mut output = OutBuffer::around(&mut output);
+
+            loop {
+                encoder.run(&mut input, &mut output).unwrap();
+
+                if input.pos == input.src.len() {
+                    break;
+                }
+            }
+            encoder.finish(&mut output).unwrap();
+
+            output.as_slice().to_vec()
+        };
+
+        let first = compress(&mut encoder, b"AbcdefAbcdefabcdef");
+        encoder.reinit().unwrap();
+        let second = compress(&mut encoder, b"Some entirely different input data");
+
+        let mut decoder = Decoder::new().unwrap();
+        for (frame, expected) in &[
+            (first, b"AbcdefAbcdefabcdef".to_vec()),
+            (second, b"Some entirely different input data".to_vec()),
+        ] {
+            let mut input = InBuffer::around(frame.as_slice());
+            let mut output = [0u8; 128];
+            let mut output = OutBuffer::around(&mut output);
+
+            loop {
+                decoder.run(&mut input, &mut output).unwrap();
+
+                if input.pos == input.src.len() {
+                    break;
+                }
+            }
+
+            assert_eq!(expected.as_slice(), output.as_slice());
+            decoder.reinit().unwrap();
+        }
+    }
+
+    #[test]
+    fn test_pledged_src_size() {
+        let data = b"AbcdefAbcdefabcdef";
+
+        let mut encoder = Encoder::new(1).unwrap();
+        encoder.set_pledged_src_size(Some(data.len() as u64)).unwrap();
+
+        let mut input = InBuffer::around(&data[..]);
+        let mut output = [0u8; 128];
+        let mut output = OutBuffer::around(&mut output);
+
+        loop {
+            encoder.run(&mut input, &mut output).unwrap();
+
+            if input.pos == input.src.len() {
+                break;
+            }
+        }
+        encoder.finish(&mut output).unwrap();
+
+        assert_eq!(
+            Decoder::content_size(output.as_slice()),
+            Some(data.len() as u64)
+        );
+    }
+
+    #[test]
+    fn test_pledged_src_size_mismatch() {
+        let mut encoder = Encoder::new(1).unwrap();
+        encoder.set_pledged_src_size(Some(100)).unwrap();
+
+        let mut input = InBuffer::around(b"too short");
+        let mut output = [0u8; 128];
+        let mut output = OutBuffer::around(&mut output);
+
+        loop {
+            encoder.run(&mut input, &mut output).unwrap();
+
+            if input.pos == input.src.len() {
+                break;
+            }
+        }
+
+        assert!(encoder.finish(&mut output).is_err());
+    }
 }